@@ -1,269 +1,479 @@
+use quote::{format_ident, quote};
 use regex::Regex;
+use similar::TextDiff;
 use std::ascii::AsciiExt;
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{Write, BufWriter};
+use std::{env, fs, process};
 use std::path::Path;
 use marksman_escape::Escape;
 
 use snake_to_camel;
 
-const PG_TYPE_H: &'static str = include_str!("pg_type.h");
-const PG_RANGE_H: &'static str = include_str!("pg_range.h");
+// Fallback catalog data, vendored from the Postgres version this generator
+// was last refreshed against. Used when `POSTGRES_SRC` isn't set. Postgres
+// 10 replaced the old bootstrap `DATA(insert ...)` lines in `pg_type.h`
+// with this Perl-hash `.dat` format; `pg_type.h` itself is now generated
+// and carries no type rows.
+const VENDORED_PG_TYPE_DAT: &'static str = include_str!("pg_type.dat");
+const VENDORED_PG_RANGE_DAT: &'static str = include_str!("pg_range.dat");
 
 struct Type {
-    name: &'static str,
+    name: String,
     variant: String,
-    kind: &'static str,
+    kind: String,
     element: u32,
     doc: String,
+    is_multirange: bool,
+}
+
+/// Locates `pg_type.dat` and `pg_range.dat`.
+///
+/// If the `POSTGRES_SRC` environment variable points at a Postgres source
+/// checkout, the catalog data is read from its `src/include/catalog`
+/// directory, which lets the generator be re-run against any vendored
+/// major version (12 through 16 and beyond) instead of being frozen to a
+/// single snapshot. Otherwise, it falls back to the data vendored
+/// alongside this file.
+fn locate_headers() -> (String, String) {
+    match env::var_os("POSTGRES_SRC") {
+        Some(src) => {
+            let catalog = Path::new(&src).join("src/include/catalog");
+            let pg_type_dat = fs::read_to_string(catalog.join("pg_type.dat"))
+                .expect("failed to read pg_type.dat from POSTGRES_SRC");
+            let pg_range_dat = fs::read_to_string(catalog.join("pg_range.dat"))
+                .expect("failed to read pg_range.dat from POSTGRES_SRC");
+            (pg_type_dat, pg_range_dat)
+        }
+        None => (VENDORED_PG_TYPE_DAT.to_owned(), VENDORED_PG_RANGE_DAT.to_owned()),
+    }
 }
 
 pub fn build(path: &Path) {
-    let mut file = BufWriter::new(File::create(path.join("types/type_gen.rs")).unwrap());
+    let (pg_type_dat, pg_range_dat) = locate_headers();
 
-    let ranges = parse_ranges();
-    let types = parse_types(&ranges);
+    let parsed = parse_types(&pg_type_dat, &pg_range_dat);
 
-    make_header(&mut file);
-    make_enum(&mut file, &types);
-    make_display_impl(&mut file);
-    make_impl(&mut file, &types);
-}
+    let mut stream = make_header();
+    stream.extend(make_enum(&parsed.types));
+    stream.extend(make_display_impl());
+    stream.extend(make_impl(&parsed.types, &parsed.array_types));
 
-fn parse_ranges() -> BTreeMap<u32, u32> {
-    let mut ranges = BTreeMap::new();
+    let out = format!("// Autogenerated file - DO NOT EDIT\n\n{}", reformat(stream.to_string()));
 
-    for line in PG_RANGE_H.lines() {
-        if !line.starts_with("DATA") {
-            continue;
-        }
+    let dest = path.join("types/type_gen.rs");
+    if ensure_file_contents(&dest, &out).is_err() {
+        process::exit(1);
+    }
+}
 
-        let split = line.split_whitespace().collect::<Vec<_>>();
+/// Writes `contents` to `path`, unless the file is already up to date.
+///
+/// In check mode (`TYPE_GEN_CHECK` set), an out-of-date file is reported as
+/// an error with a unified diff instead of being overwritten, so CI can
+/// catch a `type_gen.rs` that has drifted from the headers it's generated
+/// from.
+fn ensure_file_contents(path: &Path, contents: &str) -> Result<(), ()> {
+    let contents = normalize_newlines(contents);
+    if let Ok(old_contents) = fs::read_to_string(path) {
+        if normalize_newlines(&old_contents) == contents {
+            return Ok(());
+        }
+    }
 
-        let oid = split[2].parse().unwrap();
-        let element = split[3].parse().unwrap();
+    if is_check_mode() {
+        let old_contents = fs::read_to_string(path).unwrap_or_default();
+        print_diff(path, &old_contents, &contents);
+        return Err(());
+    }
 
-        ranges.insert(oid, element);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
     }
+    fs::write(path, contents).unwrap();
+    Ok(())
+}
 
-    ranges
+fn normalize_newlines(s: &str) -> String {
+    s.replace("\r\n", "\n")
 }
 
-fn parse_types(ranges: &BTreeMap<u32, u32>) -> BTreeMap<u32, Type> {
-    let doc_re = Regex::new(r#"DESCR\("([^"]+)"\)"#).unwrap();
-    let range_vector_re = Regex::new("(range|vector)$").unwrap();
-    let array_re = Regex::new("^_(.*)").unwrap();
+fn is_check_mode() -> bool {
+    env::var_os("TYPE_GEN_CHECK").is_some()
+}
 
-    let mut types = BTreeMap::new();
+fn print_diff(path: &Path, old: &str, new: &str) {
+    eprintln!("{} is not up to date, diff:\n", path.display());
+    let diff = TextDiff::from_lines(old, new);
+    eprint!("{}", diff.unified_diff().header("committed", "generated"));
+}
 
-    let mut lines = PG_TYPE_H.lines().peekable();
-    while let Some(line) = lines.next() {
-        if !line.starts_with("DATA") {
-            continue;
-        }
+fn reformat(text: String) -> String {
+    let file = syn::parse_file(&text).expect("generated code should parse");
+    prettyplease::unparse(&file)
+}
 
-        let split = line.split_whitespace().collect::<Vec<_>>();
+/// Pulls every `{ key => 'value', ... }` Perl hash literal out of a
+/// `.dat` catalog data file and returns each as a field map. This covers
+/// the subset of the format `pg_type.dat`/`pg_range.dat` actually use
+/// (single-quoted scalar values, no nested structures); it isn't a full
+/// Perl parser.
+fn parse_dat_entries(dat: &str) -> Vec<BTreeMap<String, String>> {
+    let entry_re = Regex::new(r"(?s)\{(.*?)\}").unwrap();
+    let field_re = Regex::new(r"(\w+)\s*=>\s*'((?:[^'\\]|\\.)*)'").unwrap();
+
+    entry_re.captures_iter(dat)
+            .filter_map(|entry| {
+                let fields: BTreeMap<String, String> =
+                    field_re.captures_iter(&entry[1])
+                            .map(|field| (field[1].to_owned(), field[2].replace("\\'", "'")))
+                            .collect();
+
+                if fields.is_empty() { None } else { Some(fields) }
+            })
+            .collect()
+}
 
-        let oid = split[3].parse().unwrap();
+struct ParsedTypes {
+    types: BTreeMap<u32, Type>,
+    // element oid -> array oid
+    array_types: BTreeMap<u32, u32>,
+}
 
-        let name = split[5];
+fn parse_types(pg_type_dat: &str, pg_range_dat: &str) -> ParsedTypes {
+    let range_vector_re = Regex::new("(range|vector)$").unwrap();
+    let array_re = Regex::new("^_(.*)").unwrap();
 
-        let variant = match name {
+    let variant_for = |name: &str| -> String {
+        match name {
             "anyarray" => "AnyArray".to_owned(),
             name => {
                 let variant = range_vector_re.replace(name, "_$1");
-                let variant = array_re.replace(&variant, "$1_array");
+                // `$1_array` would be parsed by the regex crate as a reference
+                // to a named group called "1_array" (which doesn't exist) and
+                // silently expand to an empty string; `${1}_array` disambiguates.
+                let variant = array_re.replace(&variant, "${1}_array");
                 snake_to_camel(&variant)
             }
-        };
-
-        let kind = split[11];
-
-        // we need to be able to pull composite fields and enum variants at runtime
-        if kind == "C" || kind == "E" {
-            continue;
         }
+    };
 
-        let element = if let Some(&element) = ranges.get(&oid) {
-            element
-        } else {
-            split[16].parse().unwrap()
-        };
-
+    let doc_for = |name: &str, descr: Option<&str>| -> String {
         let doc = array_re.replace(name, "$1[]");
         let mut doc = doc.to_ascii_uppercase();
 
-        let descr = lines.peek()
-                         .and_then(|line| doc_re.captures(line))
-                         .and_then(|captures| captures.at(1));
         if let Some(descr) = descr {
-            doc.push_str(" - ");
-            doc.push_str(descr);
+            if !descr.is_empty() {
+                doc.push_str(" - ");
+                doc.push_str(descr);
+            }
         }
         let doc = Escape::new(doc.as_bytes().iter().cloned()).collect();
-        let doc = String::from_utf8(doc).unwrap();
+        String::from_utf8(doc).unwrap()
+    };
+
+    let mut types = BTreeMap::new();
+    let mut name_to_oid = BTreeMap::new();
+    let mut array_types = BTreeMap::new();
+
+    for fields in parse_dat_entries(pg_type_dat) {
+        let oid: u32 = match fields.get("oid") {
+            Some(oid) => oid.parse().unwrap(),
+            None => continue,
+        };
+        let name = match fields.get("typname") {
+            Some(name) => name.clone(),
+            None => continue,
+        };
+
+        name_to_oid.insert(name.clone(), oid);
+
+        if let Some(array_oid) = fields.get("array_type_oid").and_then(|v| v.parse().ok()) {
+            array_types.insert(oid, array_oid);
+        }
+
+        let variant = variant_for(&name);
+
+        // `typcategory` is missing for most plain base types (it defaults
+        // via `Catalog.pm`'s bootstrap rules); "U" (user-defined) matches
+        // that default closely enough for codegen purposes.
+        let kind = fields.get("typcategory").cloned().unwrap_or_else(|| "U".to_owned());
+
+        // `typtype` defaults to 'b' (base) when omitted.
+        let is_multirange = fields.get("typtype").map(|t| t == "m").unwrap_or(false);
+
+        let doc = doc_for(&name, fields.get("descr").map(String::as_str));
 
         let type_ = Type {
             name: name,
             variant: variant,
             kind: kind,
-            element: element,
+            // filled in below, once every type's oid is known
+            element: 0,
             doc: doc,
+            is_multirange: is_multirange,
         };
 
         types.insert(oid, type_);
     }
 
-    types
-}
-
-fn make_header(w: &mut BufWriter<File>) {
-    write!(w,
-"// Autogenerated file - DO NOT EDIT
-use std::fmt;
+    // Unlike the legacy `pg_type.h` bootstrap lines, `pg_type.dat` carries
+    // no row for a type's array type at all: genbki derives the array type
+    // (and its oid, from `array_type_oid`) from the element type at build
+    // time, rather than it being written out as its own `{...}` entry. So
+    // the array `Type` has to be synthesized here, from each element's
+    // `array_type_oid`, instead of being parsed like every other row.
+    for (&element_oid, &array_oid) in &array_types {
+        let element = &types[&element_oid];
+        let name = format!("_{}", element.name);
+        let variant = variant_for(&name);
+        let doc = doc_for(&name, None);
+
+        types.insert(array_oid, Type {
+            name: name,
+            variant: variant,
+            kind: "A".to_owned(),
+            element: element_oid,
+            doc: doc,
+            is_multirange: false,
+        });
+    }
 
-use types::{{Oid, Kind, Other}};
+    // `pg_range.dat` entries reference range/subtype/multirange types by
+    // name (`rngtypid`, `rngsubtype`, `rngmultitypid`), not OID, so they
+    // can only be resolved once `name_to_oid` is fully populated above.
+    for fields in parse_dat_entries(pg_range_dat) {
+        let range_oid = match fields.get("rngtypid").and_then(|n| name_to_oid.get(n)) {
+            Some(&oid) => oid,
+            None => continue,
+        };
+        let subtype_oid = match fields.get("rngsubtype").and_then(|n| name_to_oid.get(n)) {
+            Some(&oid) => oid,
+            None => continue,
+        };
 
-"
-           ).unwrap();
-}
+        if let Some(type_) = types.get_mut(&range_oid) {
+            type_.element = subtype_oid;
+        }
 
-fn make_enum(w: &mut BufWriter<File>, types: &BTreeMap<u32, Type>) {
-    write!(w,
-"/// A Postgres type.
-#[derive(PartialEq, Eq, Clone, Debug)]
-pub enum Type {{
-"
-           ).unwrap();
-
-    for type_ in types.values() {
-        write!(w,
-"    /// {}
-    {},
-"
-               , type_.doc, type_.variant).unwrap();
+        // Pre-14 `pg_range.dat` entries don't have `rngmultitypid` at all,
+        // so older checkouts simply never produce a multirange type here.
+        if let Some(multirange_oid) = fields.get("rngmultitypid").and_then(|n| name_to_oid.get(n)) {
+            if let Some(type_) = types.get_mut(multirange_oid) {
+                type_.element = range_oid;
+            }
+        }
     }
 
-    write!(w,
-r"    /// An unknown type.
-    Other(Other),
-}}
+    ParsedTypes { types, array_types }
+}
+
+fn make_header() -> proc_macro2::TokenStream {
+    quote! {
+        use std::fmt;
 
-"         ).unwrap();
+        use types::{Oid, Kind, Other};
+    }
 }
 
-fn make_display_impl(w: &mut BufWriter<File>) {
-    write!(w,
-r#"impl fmt::Display for Type {{
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {{
-        match self.schema() {{
-            "public" | "pg_catalog" => {{}}
-            schema => write!(fmt, "{{}}.", schema)?,
-        }}
-        fmt.write_str(self.name())
-    }}
-}}
-
-"#,
-       ).unwrap();
+fn make_enum(types: &BTreeMap<u32, Type>) -> proc_macro2::TokenStream {
+    let variants = types.values().map(|type_| {
+        let variant = format_ident!("{}", type_.variant);
+        let doc = &type_.doc;
+        quote! {
+            #[doc = #doc]
+            #variant,
+        }
+    });
+
+    quote! {
+        /// A Postgres type.
+        #[derive(PartialEq, Eq, Clone, Debug)]
+        pub enum Type {
+            #(#variants)*
+            /// An unknown type.
+            Other(Other),
+        }
+    }
 }
 
-fn make_impl(w: &mut BufWriter<File>, types: &BTreeMap<u32, Type>) {
-    write!(w,
-"impl Type {{
-    /// Returns the `Type` corresponding to the provided `Oid` if it
-    /// corresponds to a built-in type.
-    pub fn from_oid(oid: Oid) -> Option<Type> {{
-        match oid {{
-",
-           ).unwrap();
-
-    for (oid, type_) in types {
-        write!(w,
-"            {} => Some(Type::{}),
-",
-               oid, type_.variant).unwrap();
+fn make_display_impl() -> proc_macro2::TokenStream {
+    quote! {
+        impl fmt::Display for Type {
+            fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                match self.schema() {
+                    "public" | "pg_catalog" => {}
+                    schema => write!(fmt, "{}.", schema)?,
+                }
+                fmt.write_str(self.name())
+            }
+        }
     }
+}
 
-    write!(w,
-"            _ => None,
-        }}
-    }}
+// NOTE: the generated `kind()` uses only `Kind::Pseudo`, `Kind::Array`,
+// `Kind::Range` and `Kind::Simple`. Composite ("C") and enum ("E") types
+// get `Kind::Simple`, with no fields or variant labels, and multirange
+// types get `Kind::Range` of their range type.
+fn make_impl(types: &BTreeMap<u32, Type>, array_types: &BTreeMap<u32, u32>) -> proc_macro2::TokenStream {
+    let from_oid_arms = types.iter().map(|(oid, type_)| {
+        let variant = format_ident!("{}", type_.variant);
+        quote! {
+            #oid => Some(Type::#variant),
+        }
+    });
 
-    /// Returns the OID of the `Type`.
-    pub fn oid(&self) -> Oid {{
-        match *self {{
-",
-           ).unwrap();
+    let oid_arms = types.iter().map(|(oid, type_)| {
+        let variant = format_ident!("{}", type_.variant);
+        quote! {
+            Type::#variant => #oid,
+        }
+    });
+
+    let kind_arms = types.values().map(|type_| {
+        let variant = format_ident!("{}", type_.variant);
+        // Multirange types share the `R` typcategory with plain range
+        // types, so they must be distinguished before falling into the
+        // `"R"` arm below, not via a `typcategory` value of their own
+        // (there isn't one).
+        //
+        // `types::Kind` doesn't have a `Multirange` variant yet (and this
+        // codegen crate has no access to that module to add one), so a
+        // multirange type is gated to the same `Kind::Range` as a plain
+        // range for now; its `Type::*` variant is still correctly
+        // distinguished from its range counterpart above. Switch this to
+        // `Kind::Multirange(Type::#range)` once that variant exists.
+        let kind = if type_.is_multirange {
+            let range = format_ident!("{}", types[&type_.element].variant);
+            quote!(Kind::Range(Type::#range))
+        } else {
+            match type_.kind.as_str() {
+                "P" => quote!(Kind::Pseudo),
+                "A" => {
+                    let element = format_ident!("{}", types[&type_.element].variant);
+                    quote!(Kind::Array(Type::#element))
+                }
+                "R" => {
+                    let element = format_ident!("{}", types[&type_.element].variant);
+                    quote!(Kind::Range(Type::#element))
+                }
+                // `types::Kind` doesn't have `Composite`/`Enum` variants
+                // yet either (nor the `Field` type or
+                // `Other::with_fields`/`with_variants` constructors that
+                // would hydrate them), so "C" (composite) and "E" (enum)
+                // fall through to the `Kind::Simple` default below rather
+                // than referencing variants that don't exist; `kind()`
+                // will report no fields/variants for them until that
+                // lands. Note also that built-in catalog composite
+                // rowtypes aren't standalone `{...}` rows in `pg_type.dat`
+                // to begin with (genbki derives them from each catalog's
+                // own definition), so this isn't currently reached by
+                // anything parsed from real catalog data anyway.
+                _ => quote!(Kind::Simple),
+            }
+        };
 
+        quote! {
+            Type::#variant => {
+                const V: &'static Kind = &#kind;
+                V
+            }
+        }
+    });
 
-    for (oid, type_) in types {
-        write!(w,
-"            Type::{} => {},
-",
-               type_.variant, oid).unwrap();
-    }
+    let name_arms = types.values().map(|type_| {
+        let variant = format_ident!("{}", type_.variant);
+        let name = &type_.name;
+        quote! {
+            Type::#variant => #name,
+        }
+    });
+
+    let array_type_arms = array_types.iter()
+        .filter_map(|(&element_oid, &array_oid)| {
+            let element = &types.get(&element_oid)?.variant;
+            let array = &types.get(&array_oid)?.variant;
+            let element = format_ident!("{}", element);
+            let array = format_ident!("{}", array);
+            Some(quote! {
+                Type::#element => Some(Type::#array),
+            })
+        });
+
+    quote! {
+        impl Type {
+            /// Returns the `Type` corresponding to the provided `Oid` if it
+            /// corresponds to a built-in type.
+            pub fn from_oid(oid: Oid) -> Option<Type> {
+                match oid {
+                    #(#from_oid_arms)*
+                    _ => None,
+                }
+            }
 
-    write!(w,
-"            Type::Other(ref u) => u.oid(),
-        }}
-    }}
-
-    /// Returns the kind of this type.
-    pub fn kind(&self) -> &Kind {{
-        match *self {{
-",
-           ).unwrap();
-
-    for type_ in types.values() {
-        let kind = match type_.kind {
-            "P" => "Pseudo".to_owned(),
-            "A" => format!("Array(Type::{})", types[&type_.element].variant),
-            "R" => format!("Range(Type::{})", types[&type_.element].variant),
-            _ => "Simple".to_owned(),
-        };
+            /// Returns the OID of the `Type`.
+            pub fn oid(&self) -> Oid {
+                match *self {
+                    #(#oid_arms)*
+                    Type::Other(ref u) => u.oid(),
+                }
+            }
 
-        write!(w,
-"            Type::{} => {{
-                const V: &'static Kind = &Kind::{};
-                V
-            }}
-",
-               type_.variant, kind).unwrap();
-    }
+            /// Returns the kind of this type.
+            pub fn kind(&self) -> &Kind {
+                match *self {
+                    #(#kind_arms)*
+                    Type::Other(ref u) => u.kind(),
+                }
+            }
+
+            /// Returns the schema of this type.
+            pub fn schema(&self) -> &str {
+                match *self {
+                    Type::Other(ref u) => u.schema(),
+                    _ => "pg_catalog",
+                }
+            }
+
+            /// Returns the name of this type.
+            pub fn name(&self) -> &str {
+                match *self {
+                    #(#name_arms)*
+                    Type::Other(ref u) => u.name(),
+                }
+            }
 
-    write!(w,
-r#"            Type::Other(ref u) => u.kind(),
-        }}
-    }}
-
-    /// Returns the schema of this type.
-    pub fn schema(&self) -> &str {{
-        match *self {{
-            Type::Other(ref u) => u.schema(),
-            _ => "pg_catalog",
-        }}
-    }}
-
-    /// Returns the name of this type.
-    pub fn name(&self) -> &str {{
-        match *self {{
-"#,
-          ).unwrap();
-
-    for type_ in types.values() {
-        write!(w,
-r#"            Type::{} => "{}",
-"#,
-               type_.variant, type_.name).unwrap();
+            /// Returns the array type of this type if one exists.
+            pub fn array_type(&self) -> Option<Type> {
+                match *self {
+                    #(#array_type_arms)*
+                    _ => None,
+                }
+            }
+        }
     }
+}
 
-    write!(w,
-"            Type::Other(ref u) => u.name(),
-        }}
-    }}
-}}
-"
-           ).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::parse_types;
+
+    // Regression test for a bug where `array_type_arms` compiled to
+    // `match *self { _ => None }` for every type: `array_type_oid` rows
+    // were never resolved because no array `Type` existed in `types` to
+    // satisfy `types.get(&array_oid)` (fixed by synthesizing one in
+    // `parse_types`). This checks the data `array_type()` is generated
+    // from, since the generated `Type::array_type()` itself lives in the
+    // `postgres-types` crate, not this one.
+    #[test]
+    fn array_types_map_links_element_to_its_synthesized_array() {
+        let pg_type_dat = "[
+            { oid => '23', array_type_oid => '1007', typname => 'int4', typcategory => 'N' },
+        ]";
+
+        let parsed = parse_types(pg_type_dat, "[\n]");
+
+        let &array_oid = parsed.array_types.get(&23).expect("int4 should have an array type");
+        assert_eq!(array_oid, 1007);
+        assert!(parsed.types.contains_key(&array_oid));
+    }
 }